@@ -1,29 +1,99 @@
 use {
-    crate::{
-        read_json_from_file, send_transactions_with_retry, GeneratedMerkleTree,
-        GeneratedMerkleTreeCollection,
-    },
+    crate::{read_json_from_file, GeneratedMerkleTree, GeneratedMerkleTreeCollection},
     anchor_lang::AccountDeserialize,
-    log::{error, info},
-    solana_client::nonblocking::rpc_client::RpcClient,
+    futures::{stream, StreamExt},
+    log::{error, info, warn},
+    solana_client::{
+        connection_cache::ConnectionCache, nonblocking::rpc_client::RpcClient,
+        rpc_response::RpcContactInfo,
+    },
     solana_program::{
         fee_calculator::DEFAULT_TARGET_LAMPORTS_PER_SIGNATURE, native_token::LAMPORTS_PER_SOL,
     },
+    serde::Serialize,
     solana_sdk::{
+        clock::Slot,
         commitment_config::CommitmentConfig,
+        compute_budget::ComputeBudgetInstruction,
+        hash::Hash,
         pubkey::Pubkey,
-        signature::{read_keypair_file, Signer},
+        signature::{read_keypair_file, Keypair, Signature, Signer},
         transaction::Transaction,
     },
-    std::{path::PathBuf, time::Duration},
+    std::{
+        collections::HashMap,
+        fs::File,
+        net::SocketAddr,
+        path::PathBuf,
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant},
+    },
     thiserror::Error,
     tip_distribution::{
         sdk::instruction::{upload_merkle_root_ix, UploadMerkleRootAccounts, UploadMerkleRootArgs},
         state::{Config, TipDistributionAccount},
     },
-    tokio::runtime::Builder,
+    tokio::{runtime::Builder, sync::RwLock, task::JoinHandle},
 };
 
+/// Number of upcoming leaders (inclusive of the current one) to fan each transaction out to
+/// when submitting over [`SubmissionMode::Tpu`].
+const TPU_LEADER_FANOUT: usize = 4;
+
+/// How often an unconfirmed upload transaction is re-broadcast.
+const REBROADCAST_INTERVAL: Duration = Duration::from_millis(400);
+
+/// How often the background task refreshes the blockhash used to re-sign outstanding upload
+/// transactions.
+const BLOCKHASH_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait between retries when a blockhash fetch fails transiently.
+const BLOCKHASH_POLL_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// `getMultipleAccounts` refuses more than this many pubkeys in a single request.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+/// Upper bound on the number of `getMultipleAccounts` chunks in flight at once.
+const MAX_CONCURRENT_ACCOUNT_FETCHES: usize = 10;
+
+/// A single `upload_merkle_root_ix` call is cheap; this is a safe upper bound on the compute
+/// units it consumes so `set_compute_unit_limit` doesn't leave headroom that inflates the
+/// effective priority fee.
+const UPLOAD_IX_COMPUTE_UNIT_LIMIT: u32 = 20_000;
+
+/// Resolves the micro-lamports-per-CU price to attach to every upload transaction. An explicit
+/// `--priority-fee` always wins; otherwise fall back to the max of the recent prioritization
+/// fees observed on the tip-distribution program, so uploads price themselves against current
+/// congestion instead of landing with no fee at all.
+async fn resolve_priority_fee_micro_lamports(
+    rpc_client: &RpcClient,
+    tip_distribution_program_id: &Pubkey,
+    priority_fee_micro_lamports: Option<u64>,
+) -> u64 {
+    if let Some(priority_fee_micro_lamports) = priority_fee_micro_lamports {
+        return priority_fee_micro_lamports;
+    }
+
+    match rpc_client
+        .get_recent_prioritization_fees(&[*tip_distribution_program_id])
+        .await
+    {
+        Ok(fees) => fees
+            .into_iter()
+            .map(|fee| fee.prioritization_fee)
+            .max()
+            .unwrap_or_default(),
+        Err(e) => {
+            // `getRecentPrioritizationFees` isn't universally implemented; this only runs as the
+            // automatic default, so an unsupported-method error shouldn't crash an otherwise
+            // healthy run.
+            warn!("failed to fetch recent prioritization fees, defaulting to 0: {e}");
+            0
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum MerkleRootUploadError {
     #[error(transparent)]
@@ -33,18 +103,638 @@ pub enum MerkleRootUploadError {
     JsonError(#[from] serde_json::Error),
 }
 
+/// Where upload transactions get sent. `Rpc` preserves the historical behavior of pushing
+/// transactions through the configured RPC node's send path; `Tpu` forwards the signed wire
+/// transactions directly to the leader schedule over QUIC, which lands more reliably when the
+/// RPC node's send path is congested.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SubmissionMode {
+    Rpc,
+    Tpu,
+}
+
+impl FromStr for SubmissionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rpc" => Ok(Self::Rpc),
+            "tpu" => Ok(Self::Tpu),
+            _ => Err(format!("invalid submission mode: {s}, expected `rpc` or `tpu`")),
+        }
+    }
+}
+
+/// What happened to a single tree's root during an `upload_merkle_root` run.
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum UploadDecision {
+    /// On-chain root already matches, or has already been claimed against; nothing to do.
+    Skipped,
+    /// `--dry-run` was set; this tree would have been uploaded.
+    WouldUpload,
+    /// The upload transaction was sent.
+    Uploaded,
+    /// The authority's balance can't cover uploading every tree that needs a new root.
+    InsufficientFunds,
+    /// No loaded keypair's pubkey matches this tree's `merkle_root_upload_authority`.
+    NoMatchingAuthority,
+}
+
+/// One row of the `--report` output: what an `upload_merkle_root` run decided, and did, for a
+/// single `TipDistributionAccount`.
+#[derive(Serialize, Debug, Clone)]
+struct TreeUploadReportEntry {
+    tip_distribution_account: Pubkey,
+    old_merkle_root: Option<[u8; 32]>,
+    new_merkle_root: [u8; 32],
+    max_total_claim: u64,
+    max_num_nodes: u64,
+    decision: UploadDecision,
+    signature: Option<Signature>,
+    confirmed: Option<bool>,
+}
+
+/// Picks the `--report` decision for a scanned tree. `insufficient_funds` and `dry_run` can both
+/// be set (a dry run whose authority lacks the balance), in which case `InsufficientFunds` wins;
+/// together with `needs_upload` they fully determine the decision, so it can't drift out of sync
+/// with which branch of [`process_authority_group`] produced it.
+fn decision_for_scan(needs_upload: bool, insufficient_funds: bool, dry_run: bool) -> UploadDecision {
+    match (needs_upload, insufficient_funds, dry_run) {
+        (false, _, _) => UploadDecision::Skipped,
+        (true, true, _) => UploadDecision::InsufficientFunds,
+        (true, false, true) => UploadDecision::WouldUpload,
+        (true, false, false) => UploadDecision::Uploaded,
+    }
+}
+
+/// Builds a tree's `--report` row from the outcome of its needs-upload scan.
+fn report_entry(
+    tree: &GeneratedMerkleTree,
+    old_merkle_root: Option<[u8; 32]>,
+    needs_upload: bool,
+    insufficient_funds: bool,
+    dry_run: bool,
+) -> TreeUploadReportEntry {
+    TreeUploadReportEntry {
+        tip_distribution_account: tree.tip_distribution_account,
+        old_merkle_root,
+        new_merkle_root: tree.merkle_root.to_bytes(),
+        max_total_claim: tree.max_total_claim,
+        max_num_nodes: tree.max_num_nodes,
+        decision: decision_for_scan(needs_upload, insufficient_funds, dry_run),
+        signature: None,
+        confirmed: None,
+    }
+}
+
+/// Loads every keypair referenced by `keypair_paths`. An entry that's a directory contributes
+/// one keypair per file directly inside it that actually parses as one (non-recursive); operator
+/// keypair directories routinely pick up stray files (`.DS_Store`, backups, a README), so a file
+/// that fails to parse is skipped with a warning rather than aborting the whole run. A path given
+/// directly is read as a single keypair file and still fails loudly, since a bad explicit
+/// `--keypair-path` is a misconfiguration the operator should hear about immediately.
+fn load_keypairs(keypair_paths: &[PathBuf]) -> Vec<Keypair> {
+    let mut keypairs = Vec::new();
+    for path in keypair_paths {
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path).expect("read keypair directory") {
+                let entry_path = entry.expect("read keypair directory entry").path();
+                if !entry_path.is_file() {
+                    continue;
+                }
+                match read_keypair_file(&entry_path) {
+                    Ok(keypair) => keypairs.push(keypair),
+                    Err(e) => warn!(
+                        "skipping {}, not a valid keypair file: {e}",
+                        entry_path.display()
+                    ),
+                }
+            }
+        } else {
+            keypairs.push(
+                read_keypair_file(path)
+                    .unwrap_or_else(|_| panic!("read keypair file {}", path.display())),
+            );
+        }
+    }
+    keypairs
+}
+
+/// Converts a `set_compute_unit_price` rate into the lamports it adds to a single upload
+/// transaction's fee, given [`UPLOAD_IX_COMPUTE_UNIT_LIMIT`].
+fn priority_fee_lamports_per_tx(priority_fee_micro_lamports: u64) -> u64 {
+    (priority_fee_micro_lamports * UPLOAD_IX_COMPUTE_UNIT_LIMIT as u64) / 1_000_000
+}
+
+/// Lamports an authority needs on hand to cover uploading `num_trees_needing_upload` trees at
+/// `priority_fee_micro_lamports`. A heuristic: assumes every one of those trees' transactions
+/// lands on the first try, so it's a lower bound, not an exact cost.
+fn desired_balance_lamports(num_trees_needing_upload: u64, priority_fee_micro_lamports: u64) -> u64 {
+    num_trees_needing_upload
+        * (DEFAULT_TARGET_LAMPORTS_PER_SIGNATURE + priority_fee_lamports_per_tx(priority_fee_micro_lamports))
+}
+
+/// Decides whether `new_root` still needs to be uploaded, given the merkle root already recorded
+/// on-chain (`None` if the account doesn't exist yet or has no root uploaded). Returns
+/// `(needs_upload, old_root)`; a root that's already claimed against is left alone even if it
+/// differs, since overwriting it would invalidate in-flight claims.
+fn tree_needs_upload(
+    new_root: [u8; 32],
+    existing_root: Option<(u64, [u8; 32])>,
+) -> (bool, Option<[u8; 32]>) {
+    match existing_root {
+        Some((total_funds_claimed, root)) => (total_funds_claimed == 0 && root != new_root, Some(root)),
+        None => (true, None),
+    }
+}
+
+/// Writes `report` as pretty-printed JSON to `report_path`, overwriting any existing file.
+fn write_report(
+    report_path: &PathBuf,
+    report: &[TreeUploadReportEntry],
+) -> Result<(), MerkleRootUploadError> {
+    let file = File::create(report_path)?;
+    serde_json::to_writer_pretty(file, report)?;
+    Ok(())
+}
+
+/// A small, refreshable view of the leader schedule used to pick which TPU sockets to fan
+/// upload transactions out to.
+struct LeaderTpuCache {
+    /// Slot -> leader identity, covering the current epoch's schedule.
+    slot_leaders: HashMap<Slot, Pubkey>,
+    /// Leader identity -> TPU QUIC socket address, sourced from `get_cluster_nodes`.
+    leader_tpu_quic: HashMap<Pubkey, SocketAddr>,
+}
+
+impl LeaderTpuCache {
+    async fn fetch(rpc_client: &RpcClient) -> Self {
+        let cluster_nodes = rpc_client
+            .get_cluster_nodes()
+            .await
+            .expect("get_cluster_nodes");
+        let leader_tpu_quic = cluster_nodes
+            .into_iter()
+            .filter_map(|node: RpcContactInfo| {
+                let pubkey = Pubkey::from_str(&node.pubkey).ok()?;
+                let tpu_quic = node.tpu_quic?;
+                Some((pubkey, tpu_quic))
+            })
+            .collect();
+
+        let leader_schedule = rpc_client
+            .get_leader_schedule(None)
+            .await
+            .expect("get_leader_schedule")
+            .unwrap_or_default();
+        let mut slot_leaders = HashMap::new();
+        for (identity, slots) in leader_schedule {
+            let Ok(identity) = Pubkey::from_str(&identity) else {
+                continue;
+            };
+            for relative_slot in slots {
+                slot_leaders.insert(relative_slot as Slot, identity);
+            }
+        }
+
+        Self {
+            slot_leaders,
+            leader_tpu_quic,
+        }
+    }
+
+    /// Returns the TPU QUIC sockets for the next `fanout` leaders starting with the leader of
+    /// `relative_slot`, relative to the start of the epoch the schedule was fetched for. A leader
+    /// holding several consecutive slots counts once; the window ends at the first slot the
+    /// schedule has no leader for, and leaders with no known socket are left out.
+    fn leaders_for_window(&self, relative_slot: Slot, fanout: usize) -> Vec<SocketAddr> {
+        let mut leaders: Vec<&Pubkey> = Vec::with_capacity(fanout);
+        let mut slot = relative_slot;
+        while leaders.len() < fanout {
+            let Some(identity) = self.slot_leaders.get(&slot) else {
+                break;
+            };
+            if leaders.last() != Some(&identity) {
+                leaders.push(identity);
+            }
+            slot += 1;
+        }
+        leaders
+            .into_iter()
+            .filter_map(|identity| self.leader_tpu_quic.get(identity))
+            .copied()
+            .collect()
+    }
+}
+
+/// Picks which signature to report for a tree: the one that actually satisfied commitment, if
+/// any, since an earlier round's signature can be the one that lands while every later
+/// resubmission never confirms. Falls back to the most recently (re)sent signature when the tree
+/// never confirmed, so an unconfirmed report row still points at *a* transaction to inspect.
+fn report_signature(confirmed_signature: Option<Signature>, signatures_sent: &[Signature]) -> Signature {
+    confirmed_signature
+        .unwrap_or_else(|| *signatures_sent.last().expect("at least one signature sent"))
+}
+
+/// Fetches `get_latest_blockhash`, retrying transient RPC failures instead of propagating them,
+/// since a single hiccup shouldn't stall an otherwise healthy upload run.
+async fn poll_get_latest_blockhash(rpc_client: &RpcClient) -> Hash {
+    loop {
+        match rpc_client.get_latest_blockhash().await {
+            Ok(blockhash) => return blockhash,
+            Err(e) => {
+                warn!("failed to poll latest blockhash, retrying: {e}");
+                tokio::time::sleep(BLOCKHASH_POLL_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Keeps a shared, periodically-refreshed view of the latest blockhash so a long-running
+/// upload batch doesn't sign its tail transactions against a hash that's already expired.
+struct BlockhashRefresher {
+    blockhash: Arc<RwLock<Hash>>,
+    refresh_task: JoinHandle<()>,
+}
+
+impl BlockhashRefresher {
+    async fn new(rpc_client: Arc<RpcClient>) -> Self {
+        let blockhash = Arc::new(RwLock::new(poll_get_latest_blockhash(&rpc_client).await));
+        let refresh_task = tokio::spawn({
+            let blockhash = blockhash.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(BLOCKHASH_REFRESH_INTERVAL).await;
+                    let latest = poll_get_latest_blockhash(&rpc_client).await;
+                    *blockhash.write().await = latest;
+                }
+            }
+        });
+
+        Self {
+            blockhash,
+            refresh_task,
+        }
+    }
+
+    async fn current(&self) -> Hash {
+        *self.blockhash.read().await
+    }
+}
+
+impl Drop for BlockhashRefresher {
+    fn drop(&mut self) {
+        self.refresh_task.abort();
+    }
+}
+
+/// Drives the retry/resubmit loop shared by both submission modes: each round it re-signs every
+/// still-unconfirmed tree's upload transaction against the freshest blockhash tracked by
+/// `blockhash_refresher`, sends the batch, waits [`REBROADCAST_INTERVAL`], then drops whichever
+/// transactions have since confirmed. Runs until every tree is confirmed or `max_retry_duration`
+/// elapses.
+async fn submit_upload_transactions(
+    rpc_client: &RpcClient,
+    trees: &[GeneratedMerkleTree],
+    build_tx: impl Fn(&GeneratedMerkleTree, Hash) -> Transaction,
+    blockhash_refresher: &BlockhashRefresher,
+    submission_mode: SubmissionMode,
+    max_retry_duration: Duration,
+) -> HashMap<Pubkey, (Signature, bool)> {
+    let connection_cache = matches!(submission_mode, SubmissionMode::Tpu)
+        .then(|| ConnectionCache::new_quic("tip-distributor-tpu-client", 8));
+    // Fetched once up front rather than every rebroadcast: `get_cluster_nodes` +
+    // `get_leader_schedule` are the heaviest calls the RPC node offers, and the leader schedule
+    // doesn't change within a single upload run's `max_retry_duration`.
+    let leader_cache = match submission_mode {
+        SubmissionMode::Tpu => Some(LeaderTpuCache::fetch(rpc_client).await),
+        SubmissionMode::Rpc => None,
+    };
+
+    let mut unconfirmed: Vec<&GeneratedMerkleTree> = trees.iter().collect();
+    // Every signature ever sent for a tree, not just the most recent one: a transaction signed
+    // before a blockhash refresh is still valid and can land after the refresh, so its signature
+    // has to stay checkable even once a fresher one has been broadcast in its place.
+    let mut all_signatures: HashMap<Pubkey, Vec<Signature>> = HashMap::new();
+    // The signature that actually confirmed for each tree, which isn't necessarily the last one
+    // sent: a transaction can land after a later round has already re-signed and resubmitted it.
+    let mut confirmed_signature: HashMap<Pubkey, Signature> = HashMap::new();
+    let start = Instant::now();
+
+    while !unconfirmed.is_empty() && start.elapsed() < max_retry_duration {
+        let blockhash = blockhash_refresher.current().await;
+        let transactions: Vec<Transaction> = unconfirmed
+            .iter()
+            .map(|tree| build_tx(tree, blockhash))
+            .collect();
+
+        match submission_mode {
+            SubmissionMode::Rpc => {
+                for tx in &transactions {
+                    if let Err(e) = rpc_client.send_transaction(tx).await {
+                        warn!("failed to send upload tx {}: {e}", tx.signatures[0]);
+                    }
+                }
+            }
+            SubmissionMode::Tpu => {
+                let leader_cache = leader_cache
+                    .as_ref()
+                    .expect("leader cache fetched for Tpu mode");
+                let epoch_info = rpc_client.get_epoch_info().await.expect("get_epoch_info");
+                let leaders =
+                    leader_cache.leaders_for_window(epoch_info.slot_index, TPU_LEADER_FANOUT);
+
+                if leaders.is_empty() {
+                    warn!("no leaders resolved for upcoming slots, falling back to rebroadcast wait");
+                }
+
+                for tx in &transactions {
+                    let wire_transaction = bincode::serialize(tx).expect("serialize transaction");
+                    for tpu_addr in &leaders {
+                        if let Ok(conn) = connection_cache
+                            .as_ref()
+                            .expect("connection cache initialized for Tpu mode")
+                            .get_connection(tpu_addr)
+                        {
+                            if let Err(e) = conn.send_data_async(wire_transaction.clone()) {
+                                warn!("failed to send upload tx to {tpu_addr}: {e}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        for (tree, tx) in unconfirmed.iter().zip(&transactions) {
+            all_signatures
+                .entry(tree.tip_distribution_account)
+                .or_default()
+                .push(tx.signatures[0]);
+        }
+
+        tokio::time::sleep(REBROADCAST_INTERVAL).await;
+
+        // Check every signature sent so far for each still-unconfirmed tree, not just this
+        // round's, since an earlier round's transaction can still land on-chain after it's been
+        // superseded by a fresher resubmission.
+        let mut signature_to_tree: HashMap<Signature, Pubkey> = HashMap::new();
+        for tree in &unconfirmed {
+            for signature in all_signatures
+                .get(&tree.tip_distribution_account)
+                .into_iter()
+                .flatten()
+            {
+                signature_to_tree.insert(*signature, tree.tip_distribution_account);
+            }
+        }
+        let signatures: Vec<Signature> = signature_to_tree.keys().copied().collect();
+        let statuses = rpc_client
+            .get_signature_statuses(&signatures)
+            .await
+            .expect("get_signature_statuses")
+            .value;
+
+        let newly_confirmed: HashMap<Pubkey, Signature> = signatures
+            .iter()
+            .zip(statuses)
+            .filter_map(|(signature, status)| match status {
+                Some(status) if status.satisfies_commitment(CommitmentConfig::confirmed()) => {
+                    Some((signature_to_tree[signature], *signature))
+                }
+                _ => None,
+            })
+            .collect();
+
+        unconfirmed.retain(|tree| !newly_confirmed.contains_key(&tree.tip_distribution_account));
+        confirmed_signature.extend(newly_confirmed);
+    }
+
+    if !unconfirmed.is_empty() {
+        error!(
+            "{} upload transactions still unconfirmed after {:?}",
+            unconfirmed.len(),
+            max_retry_duration
+        );
+    }
+
+    all_signatures
+        .into_iter()
+        .map(|(tip_distribution_account, signatures)| {
+            let confirmed = confirmed_signature.contains_key(&tip_distribution_account);
+            let signature = report_signature(
+                confirmed_signature.get(&tip_distribution_account).copied(),
+                &signatures,
+            );
+            (tip_distribution_account, (signature, confirmed))
+        })
+        .collect()
+}
+
+/// Runs the full upload flow — balance check, needs-upload scan, and submission — for a single
+/// merkle-root-upload authority's trees, signing every transaction with its own keypair. Returns
+/// the report rows produced for this authority, regardless of `report_path`, so the caller can
+/// aggregate across authorities before deciding whether to write them out.
+#[allow(clippy::too_many_arguments)]
+async fn process_authority_group(
+    rpc_client: Arc<RpcClient>,
+    tip_distribution_program_id: &Pubkey,
+    tip_distribution_config: Pubkey,
+    keypair: &Keypair,
+    trees: Vec<GeneratedMerkleTree>,
+    submission_mode: SubmissionMode,
+    priority_fee_micro_lamports: u64,
+    dry_run: bool,
+    max_retry_duration: Duration,
+) -> Vec<TreeUploadReportEntry> {
+    info!(
+        "authority {}: {} trees to upload",
+        keypair.pubkey(),
+        trees.len()
+    );
+
+    // (tree, needs_upload, old root currently on-chain) for every candidate tree, so the report
+    // can record a decision even for trees that are skipped.
+    let scanned_trees: Vec<(GeneratedMerkleTree, bool, Option<[u8; 32]>)> = stream::iter(
+        trees.chunks(MAX_MULTIPLE_ACCOUNTS).map(|chunk| {
+            let rpc_client = &rpc_client;
+            async move {
+                let pubkeys: Vec<Pubkey> = chunk
+                    .iter()
+                    .map(|tree| tree.tip_distribution_account)
+                    .collect();
+                let accounts = rpc_client
+                    .get_multiple_accounts(&pubkeys)
+                    .await
+                    .expect("get_multiple_accounts");
+
+                chunk
+                    .iter()
+                    .zip(accounts)
+                    .map(|(tree, maybe_account)| {
+                        // account doesn't exist yet, treat the same as no root uploaded
+                        let existing_root = maybe_account.map(|account| {
+                            let mut data = account.data.as_slice();
+                            let fetched_tip_distribution_account =
+                                TipDistributionAccount::try_deserialize(&mut data)
+                                    .expect("failed to deserialize tip_distribution_account state");
+                            fetched_tip_distribution_account
+                                .merkle_root
+                                .map(|merkle_root| {
+                                    (merkle_root.total_funds_claimed, merkle_root.root)
+                                })
+                        });
+                        let (needs_upload, old_root) =
+                            tree_needs_upload(tree.merkle_root.to_bytes(), existing_root.flatten());
+                        (tree.clone(), needs_upload, old_root)
+                    })
+                    .collect::<Vec<_>>()
+            }
+        }),
+    )
+    .buffer_unordered(MAX_CONCURRENT_ACCOUNT_FETCHES)
+    .collect::<Vec<_>>()
+    .await
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let trees_needing_update: Vec<GeneratedMerkleTree> = scanned_trees
+        .iter()
+        .filter(|(_, needs_upload, _)| *needs_upload)
+        .map(|(tree, _, _)| tree.clone())
+        .collect();
+
+    info!(
+        "authority {}: {} trees need uploading",
+        keypair.pubkey(),
+        trees_needing_update.len()
+    );
+
+    // heuristic to make sure we have enough funds to cover execution; only the trees that
+    // actually need a new root count against the balance, so an up-to-date tree never gets
+    // mislabeled as a funding failure.
+    let initial_balance = rpc_client
+        .get_balance(&keypair.pubkey())
+        .await
+        .expect("failed to get balance");
+    let desired_balance = desired_balance_lamports(
+        trees_needing_update.len() as u64,
+        priority_fee_micro_lamports,
+    );
+    if initial_balance < desired_balance {
+        let sol_to_deposit = (desired_balance - initial_balance + LAMPORTS_PER_SOL - 1) / LAMPORTS_PER_SOL; // rounds up to nearest sol
+        error!("Expected to have at least {} lamports in {}, current balance is {} lamports, deposit {} SOL to continue.",
+               desired_balance, &keypair.pubkey(), initial_balance, sol_to_deposit);
+        return scanned_trees
+            .iter()
+            .map(|(tree, needs_upload, old_merkle_root)| {
+                report_entry(tree, *old_merkle_root, *needs_upload, true, dry_run)
+            })
+            .collect();
+    }
+
+    let mut report: Vec<TreeUploadReportEntry> = scanned_trees
+        .iter()
+        .map(|(tree, needs_upload, old_merkle_root)| {
+            report_entry(tree, *old_merkle_root, *needs_upload, false, dry_run)
+        })
+        .collect();
+
+    if dry_run {
+        for entry in &report {
+            info!(
+                "dry run: authority {} tree {} decision={:?} old_root={:?} new_root={:?}",
+                keypair.pubkey(),
+                entry.tip_distribution_account,
+                entry.decision,
+                entry.old_merkle_root,
+                entry.new_merkle_root
+            );
+        }
+        info!(
+            "dry run: authority {} would upload {} trees",
+            keypair.pubkey(),
+            trees_needing_update.len()
+        );
+        return report;
+    }
+
+    let build_tx = |tree: &GeneratedMerkleTree, blockhash: Hash| -> Transaction {
+        let upload_ix = upload_merkle_root_ix(
+            *tip_distribution_program_id,
+            UploadMerkleRootArgs {
+                root: tree.merkle_root.to_bytes(),
+                max_total_claim: tree.max_total_claim,
+                max_num_nodes: tree.max_num_nodes,
+            },
+            UploadMerkleRootAccounts {
+                config: tip_distribution_config,
+                merkle_root_upload_authority: keypair.pubkey(),
+                tip_distribution_account: tree.tip_distribution_account,
+            },
+        );
+        let compute_unit_limit_ix =
+            ComputeBudgetInstruction::set_compute_unit_limit(UPLOAD_IX_COMPUTE_UNIT_LIMIT);
+        let compute_unit_price_ix =
+            ComputeBudgetInstruction::set_compute_unit_price(priority_fee_micro_lamports);
+        Transaction::new_signed_with_payer(
+            &[compute_unit_limit_ix, compute_unit_price_ix, upload_ix],
+            Some(&keypair.pubkey()),
+            &[keypair],
+            blockhash,
+        )
+    };
+
+    let blockhash_refresher = BlockhashRefresher::new(rpc_client.clone()).await;
+    let upload_results = submit_upload_transactions(
+        &rpc_client,
+        &trees_needing_update,
+        build_tx,
+        &blockhash_refresher,
+        submission_mode,
+        max_retry_duration,
+    )
+    .await;
+
+    for entry in &mut report {
+        if let Some((signature, confirmed)) = upload_results.get(&entry.tip_distribution_account)
+        {
+            entry.signature = Some(*signature);
+            entry.confirmed = Some(*confirmed);
+        }
+    }
+
+    report
+}
+
 pub fn upload_merkle_root(
     merkle_root_path: &PathBuf,
-    keypair_path: &PathBuf,
+    keypair_paths: &[PathBuf],
     rpc_url: &str,
     tip_distribution_program_id: &Pubkey,
+    submission_mode: SubmissionMode,
+    priority_fee_micro_lamports: Option<u64>,
+    dry_run: bool,
+    report_path: Option<PathBuf>,
 ) -> Result<(), MerkleRootUploadError> {
     // max amount of time before blockhash expires
     const MAX_RETRY_DURATION: Duration = Duration::from_secs(60);
 
     let merkle_tree: GeneratedMerkleTreeCollection =
         read_json_from_file(merkle_root_path).expect("read GeneratedMerkleTreeCollection");
-    let keypair = read_keypair_file(keypair_path).expect("read keypair file");
+    let keypair_by_authority: HashMap<Pubkey, Keypair> = load_keypairs(keypair_paths)
+        .into_iter()
+        .map(|keypair| (keypair.pubkey(), keypair))
+        .collect();
+
+    let mut trees_by_authority: HashMap<Pubkey, Vec<GeneratedMerkleTree>> = HashMap::new();
+    for tree in merkle_tree.generated_merkle_trees {
+        trees_by_authority
+            .entry(tree.merkle_root_upload_authority)
+            .or_default()
+            .push(tree);
+    }
 
     let tip_distribution_config =
         Pubkey::find_program_address(&[Config::SEED], tip_distribution_program_id).0;
@@ -56,84 +746,292 @@ pub fn upload_merkle_root(
         .expect("build runtime");
 
     runtime.block_on(async move {
-        let rpc_client =
-            RpcClient::new_with_commitment(rpc_url.to_string(), CommitmentConfig::confirmed());
-        let recent_blockhash = rpc_client
-            .get_latest_blockhash()
-            .await
-            .expect("get blockhash");
-
-        let trees: Vec<GeneratedMerkleTree> = merkle_tree
-            .generated_merkle_trees
-            .into_iter()
-            .filter(|tree| tree.merkle_root_upload_authority == keypair.pubkey())
-            .collect();
+        let rpc_client = Arc::new(RpcClient::new_with_commitment(
+            rpc_url.to_string(),
+            CommitmentConfig::confirmed(),
+        ));
 
-        info!("num trees to upload: {:?}", trees.len());
+        let priority_fee_micro_lamports = resolve_priority_fee_micro_lamports(
+            &rpc_client,
+            tip_distribution_program_id,
+            priority_fee_micro_lamports,
+        )
+        .await;
+        info!("using priority fee of {priority_fee_micro_lamports} micro-lamports/CU");
 
-        // heuristic to make sure we have enough funds to cover execution, assumes all trees need updating 
-        {
-            let initial_balance = rpc_client.get_balance(&keypair.pubkey()).await.expect("failed to get balance");
-            let desired_balance = trees.len() as u64 * DEFAULT_TARGET_LAMPORTS_PER_SIGNATURE;
-            if initial_balance < desired_balance {
-                let sol_to_deposit = (desired_balance - initial_balance + LAMPORTS_PER_SOL - 1) / LAMPORTS_PER_SOL; // rounds up to nearest sol
-                panic!("Expected to have at least {} lamports in {}, current balance is {} lamports, deposit {} SOL to continue.",
-                       desired_balance, &keypair.pubkey(), initial_balance, sol_to_deposit)
-            }
-        }
-        let mut trees_needing_update: Vec<GeneratedMerkleTree> = vec![];
-        for tree in trees {
-            let account = rpc_client
-                .get_account(&tree.tip_distribution_account)
-                .await
-                .expect("fetch expect");
-
-            let mut data = account.data.as_slice();
-            let fetched_tip_distribution_account =
-                TipDistributionAccount::try_deserialize(&mut data)
-                    .expect("failed to deserialize tip_distribution_account state");
-
-            let needs_upload = match fetched_tip_distribution_account.merkle_root {
-                Some(merkle_root) => {
-                    merkle_root.total_funds_claimed == 0
-                        && merkle_root.root != tree.merkle_root.to_bytes()
+        let mut report = Vec::new();
+        for (authority, trees) in trees_by_authority {
+            match keypair_by_authority.get(&authority) {
+                Some(keypair) => {
+                    report.extend(
+                        process_authority_group(
+                            rpc_client.clone(),
+                            tip_distribution_program_id,
+                            tip_distribution_config,
+                            keypair,
+                            trees,
+                            submission_mode,
+                            priority_fee_micro_lamports,
+                            dry_run,
+                            MAX_RETRY_DURATION,
+                        )
+                        .await,
+                    );
                 }
-                None => true,
-            };
+                None => {
+                    error!(
+                        "no keypair available for merkle root upload authority {authority}, \
+                         skipping {} trees",
+                        trees.len()
+                    );
+                    report.extend(trees.iter().map(|tree| TreeUploadReportEntry {
+                        tip_distribution_account: tree.tip_distribution_account,
+                        old_merkle_root: None,
+                        new_merkle_root: tree.merkle_root.to_bytes(),
+                        max_total_claim: tree.max_total_claim,
+                        max_num_nodes: tree.max_num_nodes,
+                        decision: UploadDecision::NoMatchingAuthority,
+                        signature: None,
+                        confirmed: None,
+                    }));
+                }
+            }
 
-            if needs_upload {
-                trees_needing_update.push(tree);
+            // Written after every authority, not just once at the end: `process_authority_group`
+            // is full of `.expect()`-ed RPC calls, and a transient failure partway through a
+            // later authority shouldn't discard the report for authorities already processed.
+            if let Some(report_path) = &report_path {
+                write_report(report_path, &report).expect("write report");
             }
         }
-
-        info!("num trees need uploading: {:?}", trees_needing_update.len());
-
-        let transactions: Vec<Transaction> = trees_needing_update
-            .iter()
-            .map(|tree| {
-                let ix = upload_merkle_root_ix(
-                    *tip_distribution_program_id,
-                    UploadMerkleRootArgs {
-                        root: tree.merkle_root.to_bytes(),
-                        max_total_claim: tree.max_total_claim,
-                        max_num_nodes: tree.max_num_nodes,
-                    },
-                    UploadMerkleRootAccounts {
-                        config: tip_distribution_config,
-                        merkle_root_upload_authority: keypair.pubkey(),
-                        tip_distribution_account: tree.tip_distribution_account,
-                    },
-                );
-                Transaction::new_signed_with_payer(
-                    &[ix],
-                    Some(&keypair.pubkey()),
-                    &[&keypair],
-                    recent_blockhash,
-                )
-            })
-            .collect();
-        send_transactions_with_retry(&rpc_client, &transactions, MAX_RETRY_DURATION).await;
     });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, solana_sdk::signature::write_keypair_file};
+
+    #[test]
+    fn load_keypairs_reads_single_files_and_directories() {
+        let temp_dir = std::env::temp_dir().join(format!(
+            "merkle_root_upload_workflow_test_{}_{}",
+            std::process::id(),
+            line!()
+        ));
+        std::fs::create_dir_all(&temp_dir).expect("create temp dir");
+
+        let single_path = temp_dir.join("single.json");
+        let single_keypair = Keypair::new();
+        write_keypair_file(&single_keypair, &single_path).expect("write single keypair");
+
+        let dir_path = temp_dir.join("authorities");
+        std::fs::create_dir_all(&dir_path).expect("create authorities dir");
+        let dir_keypair_a = Keypair::new();
+        let dir_keypair_b = Keypair::new();
+        write_keypair_file(&dir_keypair_a, dir_path.join("a.json")).expect("write keypair a");
+        write_keypair_file(&dir_keypair_b, dir_path.join("b.json")).expect("write keypair b");
+
+        let keypairs = load_keypairs(&[single_path, dir_path]);
+        let pubkeys: std::collections::HashSet<Pubkey> =
+            keypairs.iter().map(Signer::pubkey).collect();
+
+        std::fs::remove_dir_all(&temp_dir).expect("clean up temp dir");
+
+        assert_eq!(keypairs.len(), 3);
+        assert!(pubkeys.contains(&single_keypair.pubkey()));
+        assert!(pubkeys.contains(&dir_keypair_a.pubkey()));
+        assert!(pubkeys.contains(&dir_keypair_b.pubkey()));
+    }
+
+    #[test]
+    fn load_keypairs_skips_stray_non_keypair_files_in_a_directory() {
+        let temp_dir = std::env::temp_dir().join(format!(
+            "merkle_root_upload_workflow_test_{}_{}",
+            std::process::id(),
+            line!()
+        ));
+        let dir_path = temp_dir.join("authorities");
+        std::fs::create_dir_all(&dir_path).expect("create authorities dir");
+
+        let good_keypair = Keypair::new();
+        write_keypair_file(&good_keypair, dir_path.join("good.json")).expect("write keypair");
+        std::fs::write(dir_path.join(".DS_Store"), b"not a keypair").expect("write stray file");
+
+        let keypairs = load_keypairs(&[dir_path]);
+
+        std::fs::remove_dir_all(&temp_dir).expect("clean up temp dir");
+
+        assert_eq!(keypairs.len(), 1);
+        assert_eq!(keypairs[0].pubkey(), good_keypair.pubkey());
+    }
+
+    #[test]
+    fn decision_for_scan_skips_trees_that_do_not_need_uploading() {
+        // An already-up-to-date tree is Skipped regardless of funds or dry-run.
+        assert_eq!(
+            decision_for_scan(false, true, true),
+            UploadDecision::Skipped
+        );
+        assert_eq!(
+            decision_for_scan(false, false, false),
+            UploadDecision::Skipped
+        );
+    }
+
+    #[test]
+    fn decision_for_scan_flags_insufficient_funds_before_dry_run() {
+        assert_eq!(
+            decision_for_scan(true, true, true),
+            UploadDecision::InsufficientFunds
+        );
+        assert_eq!(
+            decision_for_scan(true, true, false),
+            UploadDecision::InsufficientFunds
+        );
+    }
+
+    #[test]
+    fn decision_for_scan_distinguishes_dry_run_from_a_real_upload() {
+        assert_eq!(
+            decision_for_scan(true, false, true),
+            UploadDecision::WouldUpload
+        );
+        assert_eq!(
+            decision_for_scan(true, false, false),
+            UploadDecision::Uploaded
+        );
+    }
+
+    #[test]
+    fn priority_fee_lamports_per_tx_scales_with_compute_unit_limit() {
+        assert_eq!(priority_fee_lamports_per_tx(0), 0);
+        // 1_000_000 micro-lamports/CU * UPLOAD_IX_COMPUTE_UNIT_LIMIT CUs / 1_000_000 == the limit.
+        assert_eq!(
+            priority_fee_lamports_per_tx(1_000_000),
+            UPLOAD_IX_COMPUTE_UNIT_LIMIT as u64
+        );
+    }
+
+    #[test]
+    fn desired_balance_lamports_covers_every_tree_needing_upload() {
+        assert_eq!(desired_balance_lamports(0, 1_000_000), 0);
+        assert_eq!(
+            desired_balance_lamports(3, 0),
+            3 * DEFAULT_TARGET_LAMPORTS_PER_SIGNATURE
+        );
+        assert_eq!(
+            desired_balance_lamports(2, 1_000_000),
+            2 * (DEFAULT_TARGET_LAMPORTS_PER_SIGNATURE + UPLOAD_IX_COMPUTE_UNIT_LIMIT as u64)
+        );
+    }
+
+    #[test]
+    fn tree_needs_upload_when_account_does_not_exist_yet() {
+        assert_eq!(tree_needs_upload([1; 32], None), (true, None));
+    }
+
+    #[test]
+    fn tree_needs_upload_when_root_differs_and_unclaimed() {
+        assert_eq!(
+            tree_needs_upload([2; 32], Some((0, [1; 32]))),
+            (true, Some([1; 32]))
+        );
+    }
+
+    #[test]
+    fn tree_needs_upload_skips_when_root_already_matches() {
+        assert_eq!(
+            tree_needs_upload([1; 32], Some((0, [1; 32]))),
+            (false, Some([1; 32]))
+        );
+    }
+
+    #[test]
+    fn tree_needs_upload_leaves_a_claimed_root_alone_even_if_it_differs() {
+        // Funds have already been claimed against the on-chain root; overwriting it would
+        // invalidate those claims, so it's left alone even though it differs from the new root.
+        assert_eq!(
+            tree_needs_upload([2; 32], Some((1, [1; 32]))),
+            (false, Some([1; 32]))
+        );
+    }
+
+    #[test]
+    fn report_signature_prefers_the_confirming_signature_over_the_last_sent() {
+        let first = Signature::new_unique();
+        let second = Signature::new_unique();
+        let third = Signature::new_unique();
+
+        // The first round's signature is the one that actually confirmed, even though two more
+        // rounds re-signed and resubmitted after it; the report must not point at a signature
+        // that will never show up on-chain.
+        assert_eq!(
+            report_signature(Some(first), &[first, second, third]),
+            first
+        );
+    }
+
+    #[test]
+    fn report_signature_falls_back_to_the_last_sent_when_unconfirmed() {
+        let first = Signature::new_unique();
+        let second = Signature::new_unique();
+
+        assert_eq!(report_signature(None, &[first, second]), second);
+    }
+
+    #[test]
+    fn submission_mode_from_str_parses_known_values() {
+        assert_eq!(SubmissionMode::from_str("rpc").unwrap(), SubmissionMode::Rpc);
+        assert_eq!(SubmissionMode::from_str("tpu").unwrap(), SubmissionMode::Tpu);
+        assert!(SubmissionMode::from_str("bogus").is_err());
+    }
+
+    #[test]
+    fn leaders_for_window_resolves_known_leaders_in_order() {
+        let leader_a = Pubkey::new_unique();
+        let leader_b = Pubkey::new_unique();
+        let socket_a: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        let socket_b: SocketAddr = "127.0.0.1:8002".parse().unwrap();
+
+        let cache = LeaderTpuCache {
+            slot_leaders: HashMap::from([(10, leader_a), (11, leader_b), (12, leader_a)]),
+            leader_tpu_quic: HashMap::from([(leader_a, socket_a), (leader_b, socket_b)]),
+        };
+
+        assert_eq!(
+            cache.leaders_for_window(10, 3),
+            vec![socket_a, socket_b, socket_a]
+        );
+    }
+
+    #[test]
+    fn leaders_for_window_skips_slots_with_no_known_leader() {
+        let leader_a = Pubkey::new_unique();
+        let leader_b = Pubkey::new_unique();
+        let socket_a: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        // `leader_b`'s TPU socket is unknown (e.g. missing from `get_cluster_nodes`), so its
+        // slot should be dropped rather than panicking or producing a placeholder.
+        let cache = LeaderTpuCache {
+            slot_leaders: HashMap::from([(0, leader_a), (1, leader_b)]),
+            leader_tpu_quic: HashMap::from([(leader_a, socket_a)]),
+        };
+
+        assert_eq!(cache.leaders_for_window(0, 2), vec![socket_a]);
+    }
+
+    #[test]
+    fn leaders_for_window_truncates_at_epoch_boundary() {
+        let leader = Pubkey::new_unique();
+        let socket: SocketAddr = "127.0.0.1:8001".parse().unwrap();
+        // Only the first slot of the requested window has a known leader; the schedule simply
+        // has nothing past the end of the epoch it was fetched for.
+        let cache = LeaderTpuCache {
+            slot_leaders: HashMap::from([(0, leader)]),
+            leader_tpu_quic: HashMap::from([(leader, socket)]),
+        };
+
+        assert_eq!(cache.leaders_for_window(0, 4), vec![socket]);
+    }
+}